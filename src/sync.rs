@@ -15,34 +15,301 @@
 //! Synchronization types
 //!
 //! Most importantly, a fair mutex is included
-use parking_lot::{Mutex, MutexGuard};
+use parking_lot::{ArcMutexGuard, Condvar, MappedMutexGuard, Mutex, MutexGuard, RawMutex};
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The ticket-queue bookkeeping behind a `FairMutex`
+struct TicketQueue {
+    next_ticket: AtomicU64,
+    now_serving: Mutex<u64>,
+    ticket_ready: Condvar,
+}
+
+impl TicketQueue {
+    fn new() -> TicketQueue {
+        TicketQueue {
+            next_ticket: AtomicU64::new(0),
+            now_serving: Mutex::new(0),
+            ticket_ready: Condvar::new(),
+        }
+    }
+
+    /// Draw a ticket and block until it is being served
+    fn wait_for_next_ticket(&self) -> u64 {
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        let mut serving = self.now_serving.lock();
+        while *serving != ticket {
+            self.ticket_ready.wait(&mut serving);
+        }
+        ticket
+    }
+
+    /// Check whether the next ticket is free, without drawing it yet
+    ///
+    /// The caller must follow up with `commit_ticket` once it has confirmed
+    /// the data lock is also available, so that a ticket is never drawn
+    /// unless the whole `FairMutex` can actually be acquired.
+    fn try_claim_ticket(&self) -> Option<(MutexGuard<'_, u64>, u64)> {
+        let serving = self.now_serving.try_lock()?;
+        let ticket = *serving;
+        if self.next_ticket.load(Ordering::Acquire) != ticket {
+            return None;
+        }
+        Some((serving, ticket))
+    }
+
+    /// Draw the ticket claimed by a prior `try_claim_ticket`
+    fn commit_ticket(&self, ticket: u64) -> bool {
+        self.next_ticket
+            .compare_exchange(ticket, ticket + 1, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    /// Release a held ticket and wake any waiters
+    fn advance_ticket(&self, ticket: u64) {
+        let mut serving = self.now_serving.lock();
+        *serving = ticket.wrapping_add(1);
+        drop(serving);
+        self.ticket_ready.notify_all();
+    }
+}
 
 /// A fair mutex
 ///
-/// Uses an extra lock to ensure that if one thread is waiting that it will get
-/// the lock before a single thread can re-lock it.
+/// Waiters are served in strict first-in-first-out order: each call to
+/// `lock` draws a ticket from an atomic counter, and is only granted the
+/// lock once that ticket is next in line.
 pub struct FairMutex<T> {
     /// Data
-    data: Mutex<T>,
-    /// Next-to-access
-    next: Mutex<()>,
+    data: Arc<Mutex<T>>,
+    /// Ticket-queue bookkeeping
+    queue: Arc<TicketQueue>,
 }
 
 impl<T> FairMutex<T> {
     /// Create a new fair mutex
     pub fn new(data: T) -> FairMutex<T> {
         FairMutex {
-            data: Mutex::new(data),
-            next: Mutex::new(()),
+            data: Arc::new(Mutex::new(data)),
+            queue: Arc::new(TicketQueue::new()),
         }
     }
 
     /// Lock the mutex
-    pub fn lock(&self) -> MutexGuard<'_, T> {
-        // Must bind to a temporary or the lock will be freed before going
-        // into data.lock()
-        let _next = self.next.lock();
-        self.data.lock()
+    pub fn lock(&self) -> FairMutexGuard<'_, T> {
+        let ticket = self.queue.wait_for_next_ticket();
+        FairMutexGuard {
+            queue: &self.queue,
+            ticket,
+            data: ManuallyDrop::new(self.data.lock()),
+        }
+    }
+
+    /// Try to lock the mutex without blocking
+    pub fn try_lock(&self) -> Option<FairMutexGuard<'_, T>> {
+        let (_serving, ticket) = self.queue.try_claim_ticket()?;
+        let data = self.data.try_lock()?;
+        if !self.queue.commit_ticket(ticket) {
+            return None;
+        }
+        Some(FairMutexGuard {
+            queue: &self.queue,
+            ticket,
+            data: ManuallyDrop::new(data),
+        })
+    }
+
+    /// Try to lock the mutex, giving up after `timeout` has elapsed
+    pub fn try_lock_for(&self, timeout: Duration) -> Option<FairMutexGuard<'_, T>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let mut serving = self.queue.now_serving.lock();
+            let ticket = *serving;
+            if self.queue.next_ticket.load(Ordering::Acquire) == ticket {
+                if let Some(data) = self.data.try_lock() {
+                    if self.queue.commit_ticket(ticket) {
+                        return Some(FairMutexGuard {
+                            queue: &self.queue,
+                            ticket,
+                            data: ManuallyDrop::new(data),
+                        });
+                    }
+                }
+            }
+
+            if Instant::now() >= deadline {
+                return None;
+            }
+            if self.queue.ticket_ready.wait_until(&mut serving, deadline).timed_out() {
+                return None;
+            }
+        }
+    }
+
+    /// Lock only the data mutex, skipping the ticket queue
+    pub fn lock_unfair(&self) -> UnfairMutexGuard<'_, T> {
+        UnfairMutexGuard {
+            data: self.data.lock(),
+        }
+    }
+
+    /// Lock the mutex, returning a guard that owns an `Arc` to it
+    pub fn lock_arc(self: &Arc<Self>) -> ArcFairMutexGuard<T> {
+        let ticket = self.queue.wait_for_next_ticket();
+        ArcFairMutexGuard {
+            queue: Arc::clone(&self.queue),
+            ticket,
+            data: ManuallyDrop::new(self.data.lock_arc()),
+        }
+    }
+
+    /// `Arc`-owning counterpart to `try_lock`
+    pub fn try_lock_arc(self: &Arc<Self>) -> Option<ArcFairMutexGuard<T>> {
+        let (_serving, ticket) = self.queue.try_claim_ticket()?;
+        let data = self.data.try_lock_arc()?;
+        if !self.queue.commit_ticket(ticket) {
+            return None;
+        }
+        Some(ArcFairMutexGuard {
+            queue: Arc::clone(&self.queue),
+            ticket,
+            data: ManuallyDrop::new(data),
+        })
+    }
+}
+
+/// A guard holding a ticket in a `FairMutex`'s queue
+pub struct FairMutexGuard<'a, T> {
+    queue: &'a TicketQueue,
+    ticket: u64,
+    data: ManuallyDrop<MutexGuard<'a, T>>,
+}
+
+impl<'a, T> Deref for FairMutexGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &**self.data
+    }
+}
+
+impl<'a, T> DerefMut for FairMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut **self.data
+    }
+}
+
+impl<'a, T> Drop for FairMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        // Release the data lock before waking the next waiter, so it never
+        // contends on a mutex that hasn't actually been released yet.
+        unsafe { ManuallyDrop::drop(&mut self.data) };
+        self.queue.advance_ticket(self.ticket);
+    }
+}
+
+impl<'a, T> FairMutexGuard<'a, T> {
+    /// Narrow a guard down to one field of the data it protects
+    pub fn map<U, F>(orig: FairMutexGuard<'a, T>, f: F) -> MappedFairMutexGuard<'a, U>
+    where
+        F: FnOnce(&mut T) -> &mut U,
+    {
+        let orig = ManuallyDrop::new(orig);
+        let queue = orig.queue;
+        let ticket = orig.ticket;
+        // SAFETY: `orig` is wrapped in `ManuallyDrop`, so its own `Drop`
+        // (which would advance the ticket queue) never runs; `data` is read
+        // out exactly once and handed to the returned guard instead.
+        let data = unsafe { ptr::read(&*orig.data) };
+
+        MappedFairMutexGuard {
+            queue,
+            ticket,
+            data: ManuallyDrop::new(MutexGuard::map(data, f)),
+        }
+    }
+}
+
+/// `Arc`-owning counterpart to `FairMutexGuard`
+pub struct ArcFairMutexGuard<T> {
+    queue: Arc<TicketQueue>,
+    ticket: u64,
+    data: ManuallyDrop<ArcMutexGuard<RawMutex, T>>,
+}
+
+impl<T> Deref for ArcFairMutexGuard<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &**self.data
+    }
+}
+
+impl<T> DerefMut for ArcFairMutexGuard<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut **self.data
+    }
+}
+
+impl<T> Drop for ArcFairMutexGuard<T> {
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut self.data) };
+        self.queue.advance_ticket(self.ticket);
+    }
+}
+
+/// A guard narrowed to one field via `FairMutexGuard::map`
+pub struct MappedFairMutexGuard<'a, U> {
+    queue: &'a TicketQueue,
+    ticket: u64,
+    data: ManuallyDrop<MappedMutexGuard<'a, U>>,
+}
+
+impl<'a, U> Deref for MappedFairMutexGuard<'a, U> {
+    type Target = U;
+    fn deref(&self) -> &U {
+        &**self.data
+    }
+}
+
+impl<'a, U> DerefMut for MappedFairMutexGuard<'a, U> {
+    fn deref_mut(&mut self) -> &mut U {
+        &mut **self.data
+    }
+}
+
+impl<'a, U> Drop for MappedFairMutexGuard<'a, U> {
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut self.data) };
+        self.queue.advance_ticket(self.ticket);
+    }
+}
+
+/// A guard taken via `FairMutex::lock_unfair`, bypassing the ticket queue
+pub struct UnfairMutexGuard<'a, T> {
+    data: MutexGuard<'a, T>,
+}
+
+impl<'a, T> UnfairMutexGuard<'a, T> {
+    /// Release the lock, handing it fairly to the next waiter
+    pub fn unlock_fair(self) {
+        MutexGuard::unlock_fair(self.data);
+    }
+}
+
+impl<'a, T> Deref for UnfairMutexGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.data
+    }
+}
+
+impl<'a, T> DerefMut for UnfairMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.data
     }
 }
 
@@ -50,27 +317,39 @@ use interact::access::{Access, ReflectDirect};
 use interact::climber::{ClimbError, Climber};
 use interact::deser::{self, Tracker};
 use interact::{Deser, NodeTree, Reflector};
-use std::sync::Arc;
+
+/// Placeholder shown in place of a `FairMutex`'s contents when the lock
+/// could not be acquired without blocking the reflection thread.
+fn locked_placeholder(reflector: &Arc<Reflector>) -> NodeTree {
+    Reflector::reflect(reflector, &"<locked, contents unavailable>".to_string())
+}
 
 impl<T> ReflectDirect for FairMutex<T>
     where T: Access
 {
     fn immut_reflector(&self, reflector: &Arc<Reflector>) -> NodeTree {
-        let locked = self.lock();
-        Reflector::reflect(reflector, &*locked)
+        match self.try_lock() {
+            Some(locked) => Reflector::reflect(reflector, &*locked),
+            None => locked_placeholder(reflector),
+        }
     }
 
     fn immut_climber<'a>(&self, climber: &mut Climber<'a>) -> Result<Option<NodeTree>, ClimbError> {
         let save = climber.clone();
-        let retval = {
-            let locked = self.lock();
-            climber.general_access_immut(&*locked).map(Some)
+
+        let locked = match self.try_lock() {
+            Some(locked) => locked,
+            None => return Err(ClimbError::Locked),
         };
+        let retval = climber.general_access_immut(&*locked).map(Some);
+        drop(locked);
 
         if let Err(ClimbError::NeedMutPath) = &retval {
             *climber = save;
-            let mut locked = self.lock();
-            climber.general_access_mut(&mut *locked).map(Some)
+            match self.try_lock() {
+                Some(mut locked) => climber.general_access_mut(&mut *locked).map(Some),
+                None => Err(ClimbError::Locked),
+            }
         } else {
             retval
         }
@@ -96,3 +375,91 @@ use interact::derive_interact_extern_opqaue;
 derive_interact_extern_opqaue! {
     struct FairMutex<T>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn try_lock_fails_while_held_and_succeeds_once_released() {
+        let mutex = FairMutex::new(0);
+        let held = mutex.lock();
+
+        assert!(mutex.try_lock().is_none());
+
+        drop(held);
+        assert!(mutex.try_lock().is_some());
+    }
+
+    #[test]
+    fn fifo_order_is_preserved_under_contention() {
+        let mutex = Arc::new(FairMutex::new(Vec::new()));
+
+        let gatekeeper = mutex.lock();
+
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let mutex = Arc::clone(&mutex);
+                let handle = thread::spawn(move || {
+                    mutex.lock().push(i);
+                });
+                thread::sleep(Duration::from_millis(20));
+                handle
+            })
+            .collect();
+
+        drop(gatekeeper);
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(*mutex.lock(), (0..8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn lock_unfair_round_trips_through_unlock_fair() {
+        let mutex = FairMutex::new(5);
+        {
+            let mut guard = mutex.lock_unfair();
+            *guard += 1;
+            guard.unlock_fair();
+        }
+
+        assert_eq!(*mutex.lock(), 6);
+    }
+
+    #[test]
+    fn lock_arc_is_usable_from_a_spawned_thread() {
+        let mutex = Arc::new(FairMutex::new(0));
+        let handle = {
+            let mutex = Arc::clone(&mutex);
+            thread::spawn(move || {
+                let mut guard = mutex.lock_arc();
+                *guard += 1;
+            })
+        };
+
+        handle.join().unwrap();
+        assert_eq!(*mutex.lock(), 1);
+    }
+
+    #[test]
+    fn map_guard_projects_field_and_still_releases_ticket() {
+        struct Pair {
+            a: u32,
+            b: u32,
+        }
+
+        let mutex = FairMutex::new(Pair { a: 1, b: 2 });
+        {
+            let guard = mutex.lock();
+            let mut b = FairMutexGuard::map(guard, |pair| &mut pair.b);
+            *b += 10;
+        }
+
+        let guard = mutex.lock();
+        assert_eq!(guard.a, 1);
+        assert_eq!(guard.b, 12);
+    }
+}